@@ -0,0 +1,73 @@
+//! A `tokio_util::codec` wrapper around `Message::pack`/`Message::unpack`.
+
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::message::Message;
+
+/// Frames a byte stream into `Message`s.
+///
+/// `decode` buffers until a complete msgpack value is available rather than assuming a whole
+/// `Message` arrives in a single read, so a `Framed<TcpStream, MsgpackCodec>` can be driven
+/// directly off the network regardless of how the peer's writes happen to be chunked.
+#[derive(Default)]
+pub struct MsgpackCodec;
+
+impl Decoder for MsgpackCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        let mut cursor = io::Cursor::new(&src[..]);
+        match Message::unpack(&mut cursor) {
+            Ok(message) => {
+                let consumed = cursor.position() as usize;
+                src.advance(consumed);
+                Ok(Some(message))
+            }
+            Err(ref err) if err.is_truncated() => Ok(None),
+            Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+        }
+    }
+}
+
+impl Encoder<Message> for MsgpackCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> io::Result<()> {
+        dst.extend_from_slice(&message.pack());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Notification;
+    use rmpv::Value;
+
+    #[test]
+    fn decode_waits_for_a_message_split_across_two_reads() {
+        let message = Message::Notification(Notification {
+            method: "ping".to_owned(),
+            params: vec![Value::from("hello".to_owned())],
+        });
+        let bytes = message.pack();
+        let split_at = bytes.len() / 2;
+
+        let mut codec = MsgpackCodec;
+        let mut src = BytesMut::new();
+
+        src.extend_from_slice(&bytes[..split_at]);
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+
+        src.extend_from_slice(&bytes[split_at..]);
+        assert_eq!(Some(message), codec.decode(&mut src).unwrap());
+    }
+}
@@ -0,0 +1,143 @@
+//! A typed dispatch facade layered over `Dispatch`.
+//!
+//! Instead of every handler manually destructuring `Vec<Value>` and hand-rolling an
+//! `Err(Value::from("..."))` for bad input, a `Handler` lets callers register one closure per
+//! method with concrete parameter and result types; routing, deserialization, and error
+//! formatting are handled once, centrally.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rmpv::Value;
+use rmpv::ext::{from_value, to_value};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::server::Dispatch;
+
+type Route = Box<dyn Fn(Vec<Value>) -> Result<Value, Value> + Send + Sync>;
+
+/// Errors produced by the routing layer itself, before a handler's own `Result` is consulted.
+#[derive(Debug)]
+pub enum HandlerError {
+    /// No handler was registered for the requested method.
+    UnknownMethod(String),
+    /// The request's parameters could not be deserialized into the handler's expected type.
+    InvalidParams(String),
+}
+
+impl From<HandlerError> for Value {
+    fn from(err: HandlerError) -> Value {
+        match err {
+            HandlerError::UnknownMethod(method) => Value::from(format!("unknown method: {}", method)),
+            HandlerError::InvalidParams(reason) => Value::from(format!("invalid parameters: {}", reason)),
+        }
+    }
+}
+
+/// A builder for a typed routing table of RPC methods.
+///
+/// Call `register` once per method and finish with `build` to get a cheaply-cloneable
+/// `Responder` suitable for passing to `Server::handle`.
+#[derive(Default)]
+pub struct Handler {
+    routes: HashMap<String, Route>,
+}
+
+impl Handler {
+    /// Create an empty routing table.
+    pub fn new() -> Handler {
+        Handler::default()
+    }
+
+    /// Register a handler for `method`.
+    ///
+    /// `args` are deserialized into `P` via `rmpv::ext::from_value`; a mismatch produces a
+    /// `HandlerError::InvalidParams` response without the handler being called. The handler's
+    /// `Result<R, E>` is serialized back into the RPC response.
+    pub fn register<P, R, E, F>(mut self, method: &str, handler: F) -> Handler
+        where P: DeserializeOwned,
+              R: Serialize,
+              E: Serialize,
+              F: Fn(P) -> Result<R, E> + Send + Sync + 'static
+    {
+        let route: Route = Box::new(move |args: Vec<Value>| {
+            let params: P = from_value(Value::Array(args))
+                .map_err(|err| Value::from(HandlerError::InvalidParams(err.to_string())))?;
+
+            match handler(params) {
+                Ok(result) => {
+                    to_value(&result)
+                        .map_err(|err| Value::from(format!("failed to serialize result: {}", err)))
+                }
+                Err(err) => {
+                    Err(to_value(&err).unwrap_or_else(|_| Value::from("failed to serialize handler error")))
+                }
+            }
+        });
+
+        self.routes.insert(method.to_owned(), route);
+        self
+    }
+
+    /// Freeze the routing table into a `Responder` that can be handed to `Server::handle`.
+    pub fn build(self) -> Responder {
+        Responder { routes: Arc::new(self.routes) }
+    }
+}
+
+/// A built, cheaply-cloneable routing table that dispatches each request to the method it owns.
+#[derive(Clone)]
+pub struct Responder {
+    routes: Arc<HashMap<String, Route>>,
+}
+
+impl Dispatch for Responder {
+    fn dispatch(&mut self, method: &str, args: Vec<Value>) -> Result<Value, Value> {
+        match self.routes.get(method) {
+            Some(route) => route(args),
+            None => Err(Value::from(HandlerError::UnknownMethod(method.to_owned()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn responder() -> Responder {
+        Handler::new()
+            .register("add", |(a, b): (i64, i64)| -> Result<i64, String> { Ok(a + b) })
+            .build()
+    }
+
+    #[test]
+    fn routes_a_successful_call_to_its_handler() {
+        let mut responder = responder();
+        let args = vec![Value::from(2), Value::from(3)];
+
+        assert_eq!(Ok(Value::from(5)), responder.dispatch("add", args));
+    }
+
+    #[test]
+    fn unknown_method_produces_a_structured_error() {
+        let mut responder = responder();
+
+        let err = responder.dispatch("subtract", vec![]).unwrap_err();
+        assert_eq!(Value::from(HandlerError::UnknownMethod("subtract".to_owned())), err);
+    }
+
+    #[test]
+    fn params_that_fail_to_deserialize_produce_a_structured_error() {
+        let mut responder = responder();
+        let args = vec![Value::from("not a number".to_owned()), Value::from(3)];
+
+        let err = responder.dispatch("add", args).unwrap_err();
+        match err {
+            Value::String(ref message) => {
+                assert!(message.as_str().unwrap().starts_with("invalid parameters:"))
+            }
+            other => panic!("expected an invalid-parameters error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,13 @@
+pub mod codec;
+pub mod dispatch;
+pub mod message;
+pub mod server;
+pub mod transport;
+pub mod version;
+
+pub use codec::MsgpackCodec;
+pub use dispatch::{Handler, HandlerError, Responder};
+pub use message::{Message, MessageError, Notification, Request, Response};
+pub use server::{BidirectionalDispatch, Dispatch, Server, ServerHandle};
+pub use transport::{AsyncFramedStream, ChunkCodec, Handshake, TransformKind};
+pub use version::{Capabilities, NegotiatedConnection, Negotiation, NegotiationError, ProtocolVersion};
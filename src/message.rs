@@ -1,9 +1,79 @@
+use std::error;
+use std::fmt;
 use std::io;
 use std::io::prelude::*;
 
 use rmpv;
 use rmpv::Value;
 
+/// Errors that can occur while decoding a msgpack-RPC frame.
+#[derive(Debug)]
+pub enum MessageError {
+    /// The frame ended before all of the expected fields were present.
+    Truncated,
+    /// A field was present but did not have the expected msgpack type.
+    BadType {
+        at: usize,
+        expected: &'static str,
+    },
+    /// The message type tag (the first element of the frame) was not 0, 1, or 2.
+    UnknownMsgType(i64),
+    /// The underlying msgpack value could not be decoded at all.
+    Decode(rmpv::decode::Error),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MessageError::Truncated => write!(f, "truncated msgpack-rpc frame"),
+            MessageError::BadType { at, expected } => {
+                write!(f, "expected {} at position {} of msgpack-rpc frame", expected, at)
+            }
+            MessageError::UnknownMsgType(msg_type) => {
+                write!(f, "unknown msgpack-rpc message type: {}", msg_type)
+            }
+            MessageError::Decode(ref err) => write!(f, "could not decode msgpack value: {}", err),
+        }
+    }
+}
+
+impl error::Error for MessageError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            MessageError::Decode(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl MessageError {
+    /// Whether this error means the buffered bytes simply didn't yet contain a complete frame
+    /// (so a decoder should wait for more data) as opposed to being genuinely malformed.
+    ///
+    /// `rmpv` surfaces a short read the same way it surfaces any other decode failure, so this
+    /// inspects the `io::ErrorKind` of the underlying read rather than its `Display` text, which
+    /// says nothing about EOF at all.
+    ///
+    /// `MessageError::Truncated` is deliberately excluded: it means a complete msgpack array was
+    /// read but had fewer elements than the frame requires, which more bytes won't fix.
+    pub fn is_truncated(&self) -> bool {
+        match *self {
+            MessageError::Decode(ref err) => err.kind() == io::ErrorKind::UnexpectedEof,
+            _ => false,
+        }
+    }
+}
+
+impl From<rmpv::decode::Error> for MessageError {
+    fn from(err: rmpv::decode::Error) -> MessageError {
+        MessageError::Decode(err)
+    }
+}
+
+fn get(array: &[Value], at: usize) -> Result<&Value, MessageError> {
+    array.get(at).ok_or(MessageError::Truncated)
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct Request {
     pub id: u32,
@@ -41,56 +111,56 @@ impl Message {
         }
     }
 
-    pub fn unpack<R>(reader: &mut R) -> io::Result<Message>
+    pub fn unpack<R>(reader: &mut R) -> Result<Message, MessageError>
         where R: Read
     {
-        let value = rmpv::decode::read_value(reader).expect("Could not read value from transport");
+        let value = rmpv::decode::read_value(reader)?;
 
         let array = match value {
             Value::Array(array) => array,
-            _ => panic!("Invalid msgpack-rpc message received: {:?}", value),
+            _ => return Err(MessageError::BadType { at: 0, expected: "array" }),
         };
 
-        let msg_type = match *array.get(0).unwrap() {
+        let msg_type = match *get(&array, 0)? {
             Value::Integer(msg_type) => msg_type,
-            _ => panic!(),
+            _ => return Err(MessageError::BadType { at: 0, expected: "integer" }),
         };
 
         let message = match msg_type.as_i64() {
             Some(0) => {
-                let id = if let Value::Integer(ref id) = *array.get(1).unwrap() {
-                    id.as_u64().expect("fail convert u64")
+                let id = if let Value::Integer(ref id) = *get(&array, 1)? {
+                    id.as_u64().ok_or(MessageError::BadType { at: 1, expected: "u64" })?
                 } else {
-                    panic!();
+                    return Err(MessageError::BadType { at: 1, expected: "integer" });
                 };
 
-                let method = if let Value::String(ref method) = *array.get(2).unwrap() {
-                    method
+                let method = if let Value::String(ref method) = *get(&array, 2)? {
+                    method.to_owned().into_str().ok_or(MessageError::BadType { at: 2, expected: "utf-8 string" })?
                 } else {
-                    panic!();
+                    return Err(MessageError::BadType { at: 2, expected: "string" });
                 };
 
-                let params = if let Value::Array(ref params) = *array.get(3).unwrap() {
-                    params
+                let params = if let Value::Array(ref params) = *get(&array, 3)? {
+                    params.to_owned()
                 } else {
-                    panic!();
+                    return Err(MessageError::BadType { at: 3, expected: "array" });
                 };
 
                 Message::Request(Request {
                     id: id as u32,
-                    method: method.to_owned().into_str().expect("fail convert str"),
-                    params: params.to_owned(),
+                    method,
+                    params,
                 })
             }
             Some(1) => {
-                let id = if let Value::Integer(ref id) = *array.get(1).unwrap() {
-                    id.as_u64().expect("fail convert u64")
+                let id = if let Value::Integer(ref id) = *get(&array, 1)? {
+                    id.as_u64().ok_or(MessageError::BadType { at: 1, expected: "u64" })?
                 } else {
-                    panic!();
+                    return Err(MessageError::BadType { at: 1, expected: "integer" });
                 };
 
-                let err = array.get(2).unwrap().to_owned();
-                let rpc_result = array.get(3).unwrap().to_owned();
+                let err = get(&array, 2)?.to_owned();
+                let rpc_result = get(&array, 3)?.to_owned();
 
                 let result = match err {
                     Value::Nil => Ok(rpc_result),
@@ -99,28 +169,28 @@ impl Message {
 
                 Message::Response(Response {
                     id: id as u32,
-                    result: result,
+                    result,
                 })
             }
             Some(2) => {
-                let method = if let Value::String(ref method) = *array.get(1).unwrap() {
-                    method
+                let method = if let Value::String(ref method) = *get(&array, 1)? {
+                    method.to_owned().into_str().ok_or(MessageError::BadType { at: 1, expected: "utf-8 string" })?
                 } else {
-                    panic!();
+                    return Err(MessageError::BadType { at: 1, expected: "string" });
                 };
 
-                let params = if let Value::Array(ref params) = *array.get(2).unwrap() {
-                    params
+                let params = if let Value::Array(ref params) = *get(&array, 2)? {
+                    params.to_owned()
                 } else {
-                    panic!();
+                    return Err(MessageError::BadType { at: 2, expected: "array" });
                 };
 
                 Message::Notification(Notification {
-                    method: method.to_owned().into_str().expect("fail convert str"),
-                    params: params.to_owned(),
+                    method,
+                    params,
                 })
             }
-            _ => unimplemented!(),
+            other => return Err(MessageError::UnknownMsgType(other.unwrap_or_default())),
         };
         Ok(message)
     }
@@ -1,14 +1,22 @@
+use std::collections::HashMap;
 use std::io;
-use std::io::prelude::*;
-use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::net::{SocketAddr, TcpListener as StdTcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
-use mioco;
-use mioco::tcp::TcpListener as NonblockingTcpListener;
+use futures::{SinkExt, StreamExt};
 use rmpv::Value;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::Framed;
 
-use message::Message;
-use message::Response;
-use message::Request;
+use crate::codec::MsgpackCodec;
+use crate::message::Message;
+use crate::message::Notification;
+use crate::message::Response;
+use crate::message::Request;
+use crate::transport::Handshake;
+use crate::version::{Capabilities, Negotiation, ProtocolVersion};
 
 /// A target of RPC requests.
 ///
@@ -29,28 +37,107 @@ pub trait Dispatch {
     fn notify(&mut self, method: &str, args: Vec<Value>) {}
 }
 
+/// A handle to the client connected on the other end of a `Server::handle` connection.
+///
+/// Unlike `Dispatch`, which only reacts to messages the peer sends, a `ServerHandle` lets the
+/// implementor push messages back to the peer at any time, making the connection genuinely
+/// bidirectional. Outbound messages are handed to the connection's writer task over a channel,
+/// so `notify`/`request` stay plain synchronous calls usable from `BidirectionalDispatch::dispatch`.
+///
+/// Both methods require the peer to have negotiated `Capabilities::STREAMING`; otherwise they
+/// return an `io::ErrorKind::Unsupported` error rather than send anything.
+#[derive(Clone)]
+pub struct ServerHandle {
+    outbound: mpsc::UnboundedSender<Message>,
+    next_id: Arc<AtomicUsize>,
+    pending: Arc<Mutex<HashMap<u32, oneshot::Sender<Response>>>>,
+    capabilities: Capabilities,
+}
+
+impl ServerHandle {
+    fn new(outbound: mpsc::UnboundedSender<Message>, capabilities: Capabilities) -> ServerHandle {
+        ServerHandle {
+            outbound,
+            next_id: Arc::new(AtomicUsize::new(0)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            capabilities,
+        }
+    }
+
+    /// Send a notification to the connected peer.
+    pub fn notify(&self, method: &str, params: Vec<Value>) -> io::Result<()> {
+        if !self.capabilities.contains(Capabilities::STREAMING) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                                       "peer did not negotiate streaming support"));
+        }
+
+        let message = Message::Notification(Notification {
+            method: method.to_owned(),
+            params,
+        });
+        self.outbound
+            .send(message)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection is closed"))
+    }
+
+    /// Send a server-initiated request to the connected peer, returning a receiver that resolves
+    /// once the matching `Response` comes back on the connection's normal read loop.
+    ///
+    /// Dropping the receiver before the response arrives simply discards it.
+    pub fn request(&self, method: &str, params: Vec<Value>) -> io::Result<oneshot::Receiver<Response>> {
+        if !self.capabilities.contains(Capabilities::STREAMING) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                                       "peer did not negotiate streaming support"));
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) as u32;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, reply_tx);
+
+        let message = Message::Request(Request {
+            id,
+            method: method.to_owned(),
+            params,
+        });
+        match self.outbound.send(message) {
+            Ok(()) => Ok(reply_rx),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection is closed"))
+            }
+        }
+    }
+
+    /// Resolve the pending `request` awaiting `response.id`, if there is still one outstanding.
+    fn resolve(&self, response: Response) {
+        if let Some(reply_tx) = self.pending.lock().unwrap().remove(&response.id) {
+            let _ = reply_tx.send(response);
+        }
+    }
+}
+
 pub trait BidirectionalDispatch {
     fn dispatch(&mut self,
-                client: Box<Dispatch>,
+                client: &ServerHandle,
                 method: &str,
                 args: Vec<Value>)
                 -> Result<Value, Value>;
 
-    fn notify(&mut self, client: Box<Dispatch>, method: &str, args: Vec<Value>);
+    fn notify(&mut self, client: &ServerHandle, method: &str, args: Vec<Value>);
 }
 
 impl<D> BidirectionalDispatch for D
     where D: Dispatch
 {
     fn dispatch(&mut self,
-                client: Box<Dispatch>,
+                _client: &ServerHandle,
                 method: &str,
                 args: Vec<Value>)
                 -> Result<Value, Value> {
         Dispatch::dispatch(self, method, args)
     }
 
-    fn notify(&mut self, client: Box<Dispatch>, method: &str, args: Vec<Value>) {
+    fn notify(&mut self, _client: &ServerHandle, method: &str, args: Vec<Value>) {
         Dispatch::notify(self, method, args);
     }
 }
@@ -59,7 +146,9 @@ impl<D> BidirectionalDispatch for D
 ///
 /// The server will response to RPC requests and notifications and dispatch them appropriately.
 pub struct Server {
-    listener: TcpListener,
+    listener: StdTcpListener,
+    handshake: Option<Arc<Handshake>>,
+    negotiation: Arc<Negotiation>,
 }
 
 impl Server {
@@ -69,7 +158,30 @@ impl Server {
     pub fn bind<A>(addr: A) -> io::Result<Server>
         where A: ToSocketAddrs
     {
-        TcpListener::bind(addr).map(|listener| Server { listener: listener })
+        StdTcpListener::bind(addr).map(|listener| {
+            Server {
+                listener,
+                handshake: None,
+                negotiation: Arc::new(Negotiation::new(ProtocolVersion::current(),
+                                                        Capabilities::NOTIFICATIONS |
+                                                        Capabilities::STREAMING |
+                                                        Capabilities::COMPRESSION)),
+            }
+        })
+    }
+
+    /// Require every accepted connection to negotiate a transform via `handshake`, once the
+    /// version/capability negotiation has agreed that compression is supported.
+    pub fn with_handshake(mut self, handshake: Handshake) -> Server {
+        self.handshake = Some(Arc::new(handshake));
+        self
+    }
+
+    /// Advertise a different protocol version/capability set than `Server::bind`'s default of
+    /// `ProtocolVersion::current()` with all capabilities enabled.
+    pub fn with_negotiation(mut self, negotiation: Negotiation) -> Server {
+        self.negotiation = Arc::new(negotiation);
+        self
     }
 
     /// Returns the address that this server is listening on.
@@ -82,45 +194,178 @@ impl Server {
     ///
     /// This method does not return.
     pub fn handle<D>(&self, dispatcher: D)
-        where D: BidirectionalDispatch + Dispatch + Send + Sync + Clone + 'static + Default
+        where D: BidirectionalDispatch + Send + Sync + Clone + 'static
     {
         let listener = self.listener.try_clone().unwrap();
-        let local_addr = self.local_addr().unwrap().clone();
+        listener.set_nonblocking(true).unwrap();
+        let handshake = self.handshake.clone();
+        let negotiation = self.negotiation.clone();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let listener = TcpListener::from_std(listener).unwrap();
 
-        mioco::start(move || -> io::Result<()> {
-            let listener = NonblockingTcpListener::from_listener(listener, &local_addr).unwrap();
             loop {
-                let mut conn = try!(listener.accept());
-
-                loop {
-                    let request = try!(Message::unpack(&mut conn));
-                    let mut conn = conn.try_clone().unwrap();
-
-                    let mut dispatcher = dispatcher.clone();
-                    mioco::spawn(move || -> io::Result<()> {
-                        match request {
-                            Message::Request(Request { id, method, params }) => {
-                                let result =
-                                    BidirectionalDispatch::dispatch(&mut dispatcher,
-                                                                    Box::new(D::default()),
-                                                                    &method,
-                                                                    params);
-                                let response = Message::Response(Response {
-                                    id: id,
-                                    result: result,
-                                });
-
-                                conn.write_all(&response.pack()).unwrap();
-                            }
-                            _ => unimplemented!(),
-                        }
-
-                        Ok(())
-                    });
+                let (stream, _addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        eprintln!("failed to accept connection: {}", err);
+                        continue;
+                    }
+                };
+
+                let handshake = handshake.clone();
+                let negotiation = negotiation.clone();
+                let dispatcher = dispatcher.clone();
+
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(stream, negotiation, handshake, dispatcher).await {
+                        eprintln!("closing connection: {}", err);
+                    }
+                });
+            }
+        });
+    }
+}
+
+async fn handle_connection<D>(mut stream: TcpStream,
+                               negotiation: Arc<Negotiation>,
+                               handshake: Option<Arc<Handshake>>,
+                               dispatcher: D)
+                               -> io::Result<()>
+    where D: BidirectionalDispatch + Send + Sync + Clone + 'static
+{
+    let negotiated = negotiation.accept(&mut stream)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))?;
+
+    let (outbound_tx, mut outbound_rx) = mpsc::unbounded_channel::<Message>();
+    let handle = ServerHandle::new(outbound_tx.clone(), negotiated.capabilities);
+
+    match handshake {
+        Some(ref handshake) if negotiated.capabilities.contains(Capabilities::COMPRESSION) => {
+            let transformed = handshake.accept(stream).await?;
+
+            // `AsyncFramedStream` is a single duplex object rather than a splittable
+            // `Framed` sink/stream pair, so the writer task and the read loop below share it
+            // behind a lock instead.
+            let transformed = Arc::new(tokio::sync::Mutex::new(transformed));
+            let writer = transformed.clone();
+            tokio::spawn(async move {
+                while let Some(message) = outbound_rx.recv().await {
+                    if writer.lock().await.write_message(&message).await.is_err() {
+                        break;
+                    }
                 }
+            });
+
+            loop {
+                let message = match transformed.lock().await.read_message().await {
+                    Ok(message) => message,
+                    Err(err) => {
+                        eprintln!("closing connection after malformed msgpack-rpc frame: {}", err);
+                        break;
+                    }
+                };
+
+                spawn_dispatch(message, dispatcher.clone(), handle.clone(), outbound_tx.clone());
             }
-        })
-            .unwrap()
-            .unwrap();
+        }
+        _ => {
+            let framed = Framed::new(stream, MsgpackCodec);
+            let (mut sink, mut incoming) = framed.split();
+
+            tokio::spawn(async move {
+                while let Some(message) = outbound_rx.recv().await {
+                    if sink.send(message).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            while let Some(frame) = incoming.next().await {
+                let message = match frame {
+                    Ok(message) => message,
+                    Err(err) => {
+                        eprintln!("closing connection after malformed msgpack-rpc frame: {}", err);
+                        break;
+                    }
+                };
+
+                spawn_dispatch(message, dispatcher.clone(), handle.clone(), outbound_tx.clone());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn spawn_dispatch<D>(message: Message,
+                      mut dispatcher: D,
+                      handle: ServerHandle,
+                      outbound: mpsc::UnboundedSender<Message>)
+    where D: BidirectionalDispatch + Send + Sync + Clone + 'static
+{
+    tokio::spawn(async move {
+        match message {
+            Message::Request(Request { id, method, params }) => {
+                let result = BidirectionalDispatch::dispatch(&mut dispatcher, &handle, &method, params);
+                let response = Message::Response(Response {
+                    id,
+                    result,
+                });
+                let _ = outbound.send(response);
+            }
+            Message::Notification(Notification { method, params }) => {
+                if handle.capabilities.contains(Capabilities::NOTIFICATIONS) {
+                    BidirectionalDispatch::notify(&mut dispatcher, &handle, &method, params);
+                } else {
+                    eprintln!("dropping notification for method `{}`: peer did not negotiate \
+                               notification support",
+                              method);
+                }
+            }
+            Message::Response(response) => {
+                // A response to a server-initiated `ServerHandle::request`.
+                handle.resolve(response);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_resolves_once_the_matching_response_arrives() {
+        let (outbound_tx, mut outbound_rx) = mpsc::unbounded_channel::<Message>();
+        let handle = ServerHandle::new(outbound_tx, Capabilities::STREAMING);
+
+        let reply_rx = handle.request("ping", vec![]).unwrap();
+
+        let id = match outbound_rx.try_recv().unwrap() {
+            Message::Request(Request { id, ref method, .. }) if method.as_str() == "ping" => id,
+            other => panic!("expected the outgoing ping request, got {:?}", other),
+        };
+
+        handle.resolve(Response {
+            id,
+            result: Ok(Value::from("pong".to_owned())),
+        });
+
+        let response = futures::executor::block_on(reply_rx).unwrap();
+        assert_eq!(Ok(Value::from("pong".to_owned())), response.result);
+    }
+
+    #[test]
+    fn request_and_notify_require_negotiated_streaming() {
+        let (outbound_tx, _outbound_rx) = mpsc::unbounded_channel::<Message>();
+        let handle = ServerHandle::new(outbound_tx, Capabilities::NONE);
+
+        assert_eq!(io::ErrorKind::Unsupported,
+                   handle.request("ping", vec![]).unwrap_err().kind());
+        assert_eq!(io::ErrorKind::Unsupported,
+                   handle.notify("ping", vec![]).unwrap_err().kind());
     }
 }
@@ -0,0 +1,361 @@
+//! Pluggable per-connection transforms (compression, encryption) negotiated by a small
+//! handshake before any `Message` framing begins.
+//!
+//! `Handshake` picks the highest mutually-supported `TransformKind` and hands back the matching
+//! `ChunkCodec`, which `Server::handle` drives asynchronously via `AsyncFramedStream`.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// One whole-chunk transformation, e.g. compress/decompress or encrypt/decrypt.
+///
+/// Each `Message::pack` call is encoded as exactly one chunk and each `Message::unpack` call
+/// consumes exactly one, so a codec never has to deal with partial msgpack values.
+pub trait ChunkCodec: Send {
+    fn encode_chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>>;
+    fn decode_chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+struct IdentityCodec;
+
+impl ChunkCodec for IdentityCodec {
+    fn encode_chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(chunk.to_owned())
+    }
+
+    fn decode_chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(chunk.to_owned())
+    }
+}
+
+struct ZstdCodec {
+    level: i32,
+}
+
+impl ZstdCodec {
+    fn new() -> ZstdCodec {
+        ZstdCodec { level: 0 }
+    }
+}
+
+impl ChunkCodec for ZstdCodec {
+    fn encode_chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::encode_all(chunk, self.level)
+    }
+
+    fn decode_chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::decode_all(chunk)
+    }
+}
+
+/// Which side of the connection this peer is, so `EncryptedCodec` can derive a distinct subkey
+/// per direction instead of reusing one key (and nonce space) for both.
+#[derive(Clone, Copy)]
+enum Role {
+    Accepting,
+    Connecting,
+}
+
+/// Context tag for `sodiumoxide::crypto::kdf`, identifying this crate's use of the shared key.
+const KDF_CONTEXT: [u8; 8] = *b"mprpcenc";
+
+/// Derive the two directions' secretbox subkeys from the shared `master` key, returning
+/// `(encode_key, decode_key)` for `role`.
+///
+/// Deriving distinct per-direction keys (rather than sharing one key for both directions) means
+/// the all-zero starting nonce each direction uses never collides with the other direction's: the
+/// two directions' `(key, nonce)` spaces don't overlap at all.
+fn derive_direction_keys(master: [u8; 32],
+                          role: Role)
+                          -> (sodiumoxide::crypto::secretbox::Key, sodiumoxide::crypto::secretbox::Key) {
+    use sodiumoxide::crypto::kdf;
+    use sodiumoxide::crypto::secretbox::{Key, KEYBYTES};
+
+    let master = kdf::Key(master);
+
+    let mut accepting_writes = [0u8; KEYBYTES];
+    kdf::derive_from_key(&mut accepting_writes, 1, KDF_CONTEXT, &master)
+        .expect("deriving a KEYBYTES-sized subkey cannot fail");
+
+    let mut connecting_writes = [0u8; KEYBYTES];
+    kdf::derive_from_key(&mut connecting_writes, 2, KDF_CONTEXT, &master)
+        .expect("deriving a KEYBYTES-sized subkey cannot fail");
+
+    match role {
+        Role::Accepting => (Key(accepting_writes), Key(connecting_writes)),
+        Role::Connecting => (Key(connecting_writes), Key(accepting_writes)),
+    }
+}
+
+/// An AEAD-encrypted box stream: each chunk is sealed with `sodiumoxide::crypto::secretbox`
+/// under a per-direction subkey derived from the shared key, with the nonce incremented once per
+/// chunk in each direction.
+struct EncryptedCodec {
+    encode_key: sodiumoxide::crypto::secretbox::Key,
+    decode_key: sodiumoxide::crypto::secretbox::Key,
+    encode_nonce: sodiumoxide::crypto::secretbox::Nonce,
+    decode_nonce: sodiumoxide::crypto::secretbox::Nonce,
+}
+
+impl EncryptedCodec {
+    fn new(key: [u8; 32], role: Role) -> EncryptedCodec {
+        use sodiumoxide::crypto::secretbox::{Nonce, NONCEBYTES};
+
+        let (encode_key, decode_key) = derive_direction_keys(key, role);
+
+        EncryptedCodec {
+            encode_key,
+            decode_key,
+            encode_nonce: Nonce([0; NONCEBYTES]),
+            decode_nonce: Nonce([0; NONCEBYTES]),
+        }
+    }
+}
+
+fn increment_nonce(nonce: &mut sodiumoxide::crypto::secretbox::Nonce) {
+    for byte in nonce.0.iter_mut() {
+        let (next, overflowed) = byte.overflowing_add(1);
+        *byte = next;
+        if !overflowed {
+            break;
+        }
+    }
+}
+
+impl ChunkCodec for EncryptedCodec {
+    fn encode_chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        use sodiumoxide::crypto::secretbox::seal;
+
+        let ciphertext = seal(chunk, &self.encode_nonce, &self.encode_key);
+        increment_nonce(&mut self.encode_nonce);
+        Ok(ciphertext)
+    }
+
+    fn decode_chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        use sodiumoxide::crypto::secretbox::open;
+
+        let plaintext = open(chunk, &self.decode_nonce, &self.decode_key)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt chunk"))?;
+        increment_nonce(&mut self.decode_nonce);
+        Ok(plaintext)
+    }
+}
+
+/// Wraps an async byte stream so every `write_message` becomes one length-prefixed,
+/// codec-encoded chunk and every `read_message` decodes exactly the next one.
+///
+/// Framing at the chunk level (rather than delegating to `codec::MsgpackCodec`'s partial-read
+/// buffering) is enough here because each chunk already carries its own length prefix; there is
+/// no need to detect a truncated msgpack value mid-chunk.
+pub struct AsyncFramedStream<S> {
+    inner: S,
+    codec: Box<dyn ChunkCodec>,
+}
+
+impl<S> AsyncFramedStream<S>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    pub fn new(inner: S, codec: Box<dyn ChunkCodec>) -> AsyncFramedStream<S> {
+        AsyncFramedStream {
+            inner,
+            codec,
+        }
+    }
+
+    /// Read and decode the next chunk, then parse it as a `Message`.
+    pub async fn read_message(&mut self) -> io::Result<crate::message::Message> {
+        let mut len_bytes = [0u8; 4];
+        self.inner.read_exact(&mut len_bytes).await?;
+        let len = (u32::from(len_bytes[0]) << 24) | (u32::from(len_bytes[1]) << 16) |
+                  (u32::from(len_bytes[2]) << 8) | u32::from(len_bytes[3]);
+
+        let mut chunk = vec![0u8; len as usize];
+        self.inner.read_exact(&mut chunk).await?;
+        let plaintext = self.codec.decode_chunk(&chunk)?;
+
+        let mut cursor = io::Cursor::new(plaintext);
+        crate::message::Message::unpack(&mut cursor)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+    }
+
+    /// Pack and encode `message` as the next chunk.
+    pub async fn write_message(&mut self, message: &crate::message::Message) -> io::Result<()> {
+        let chunk = self.codec.encode_chunk(&message.pack())?;
+        let len = chunk.len() as u32;
+        self.inner
+            .write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8])
+            .await?;
+        self.inner.write_all(&chunk).await
+    }
+}
+
+/// A transform both peers can agree to negotiate. Preference between transforms comes from the
+/// order of a `Handshake`'s offered list, not from the order of these variants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransformKind {
+    /// No transformation; bytes pass straight through.
+    None,
+    /// A zstd-compressed stream.
+    Zstd,
+    /// An AEAD-encrypted box stream derived from a shared key.
+    Encrypted,
+}
+
+impl TransformKind {
+    fn bit(self) -> u8 {
+        match self {
+            TransformKind::None => 0b001,
+            TransformKind::Zstd => 0b010,
+            TransformKind::Encrypted => 0b100,
+        }
+    }
+
+    fn from_bit(bit: u8) -> Option<TransformKind> {
+        match bit {
+            0b001 => Some(TransformKind::None),
+            0b010 => Some(TransformKind::Zstd),
+            0b100 => Some(TransformKind::Encrypted),
+            _ => None,
+        }
+    }
+}
+
+/// The set of transforms this peer is willing to negotiate, most preferred first.
+///
+/// Run `accept` on the side that called `TcpListener::accept` and `connect` on the side that
+/// dialed out, once per connection, before any `Message::unpack`/`pack` call.
+pub struct Handshake {
+    offered: Vec<TransformKind>,
+    key: Option<[u8; 32]>,
+}
+
+impl Handshake {
+    /// Offer `offered`, most preferred first. `TransformKind::Encrypted` requires a key set via
+    /// `with_key` before `accept`/`connect` is called.
+    pub fn new(offered: Vec<TransformKind>) -> Handshake {
+        Handshake {
+            offered,
+            key: None,
+        }
+    }
+
+    /// Supply the shared key used to derive the `Encrypted` transform.
+    pub fn with_key(mut self, key: [u8; 32]) -> Handshake {
+        self.key = Some(key);
+        self
+    }
+
+    fn offer_mask(&self) -> u8 {
+        self.offered.iter().fold(0, |mask, kind| mask | kind.bit())
+    }
+
+    fn pick(&self, peer_mask: u8) -> Option<TransformKind> {
+        self.offered.iter().cloned().find(|kind| peer_mask & kind.bit() != 0)
+    }
+
+    fn codec_for(&self, kind: TransformKind, role: Role) -> io::Result<Box<dyn ChunkCodec>> {
+        match kind {
+            TransformKind::None => Ok(Box::new(IdentityCodec)),
+            TransformKind::Zstd => Ok(Box::new(ZstdCodec::new())),
+            TransformKind::Encrypted => {
+                let key = self.key
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                                   "Encrypted transform offered without a key"))?;
+                Ok(Box::new(EncryptedCodec::new(key, role)))
+            }
+        }
+    }
+
+    /// Run the handshake as the side that accepted the connection, returning an
+    /// `AsyncFramedStream` wrapping `conn` in the negotiated transform.
+    pub async fn accept<S>(&self, mut conn: S) -> io::Result<AsyncFramedStream<S>>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let mut peer_mask = [0u8; 1];
+        conn.read_exact(&mut peer_mask).await?;
+
+        let chosen = self.pick(peer_mask[0])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no mutually supported transform"))?;
+        conn.write_all(&[chosen.bit()]).await?;
+
+        let codec = self.codec_for(chosen, Role::Accepting)?;
+        Ok(AsyncFramedStream::new(conn, codec))
+    }
+
+    /// Run the handshake as the side that dialed the connection, returning an
+    /// `AsyncFramedStream` wrapping `conn` in the negotiated transform.
+    pub async fn connect<S>(&self, mut conn: S) -> io::Result<AsyncFramedStream<S>>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        conn.write_all(&[self.offer_mask()]).await?;
+
+        let mut chosen_bit = [0u8; 1];
+        conn.read_exact(&mut chosen_bit).await?;
+        let chosen = TransformKind::from_bit(chosen_bit[0])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "peer chose an unknown transform"))?;
+
+        let codec = self.codec_for(chosen, Role::Connecting)?;
+        Ok(AsyncFramedStream::new(conn, codec))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Message, Request};
+
+    #[test]
+    fn derive_direction_keys_does_not_reuse_one_key_for_both_directions() {
+        let master = [7u8; 32];
+
+        let (accepting_encode, accepting_decode) = derive_direction_keys(master, Role::Accepting);
+        let (connecting_encode, connecting_decode) = derive_direction_keys(master, Role::Connecting);
+
+        // Each side's encode key must be the other side's decode key, and the two directions
+        // must not share a single key (which would mean both directions reuse one nonce space).
+        assert_eq!(accepting_encode, connecting_decode);
+        assert_eq!(connecting_encode, accepting_decode);
+        assert_ne!(accepting_encode, accepting_decode);
+    }
+
+    #[tokio::test]
+    async fn encrypted_chunk_round_trips_between_distinct_roles() {
+        let master = [42u8; 32];
+        let mut accepting = EncryptedCodec::new(master, Role::Accepting);
+        let mut connecting = EncryptedCodec::new(master, Role::Connecting);
+
+        let sealed = connecting.encode_chunk(b"hello from the connecting side").unwrap();
+        let opened = accepting.decode_chunk(&sealed).unwrap();
+        assert_eq!(b"hello from the connecting side".to_vec(), opened);
+
+        let sealed = accepting.encode_chunk(b"hello back").unwrap();
+        let opened = connecting.decode_chunk(&sealed).unwrap();
+        assert_eq!(b"hello back".to_vec(), opened);
+    }
+
+    #[tokio::test]
+    async fn handshake_accept_and_connect_agree_on_the_most_preferred_shared_transform() {
+        let (server_conn, client_conn) = tokio::io::duplex(4096);
+
+        let server = Handshake::new(vec![TransformKind::Encrypted, TransformKind::None])
+            .with_key([9u8; 32]);
+        let client = Handshake::new(vec![TransformKind::Zstd, TransformKind::Encrypted])
+            .with_key([9u8; 32]);
+
+        let (mut server_stream, mut client_stream) = tokio::try_join!(
+            server.accept(server_conn),
+            client.connect(client_conn)
+        )
+        .unwrap();
+
+        let message = Message::Request(Request {
+            id: 1,
+            method: "ping".to_owned(),
+            params: vec![],
+        });
+        client_stream.write_message(&message).await.unwrap();
+        let received = server_stream.read_message().await.unwrap();
+        assert_eq!(message, received);
+    }
+}
@@ -0,0 +1,176 @@
+//! Protocol version and capability negotiation, run as the very first frame on every
+//! connection `Server::handle` accepts (and the matching client connect path), ahead of any
+//! transform handshake or `Message` framing.
+
+use std::cmp;
+use std::io;
+use std::ops::{BitAnd, BitOr};
+
+use rmpv::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::message::{Message, Response};
+
+/// The wire protocol version a peer speaks.
+///
+/// Peers with different `major` versions are assumed incompatible; a difference in `minor`
+/// just means one side may not understand the other's newer, backwards-compatible additions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl ProtocolVersion {
+    /// The version this build of the crate speaks.
+    pub fn current() -> ProtocolVersion {
+        ProtocolVersion { major: 1, minor: 0 }
+    }
+}
+
+/// A bitset of optional protocol features a peer supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// The peer will send and correctly handle `Message::Notification` frames.
+    pub const NOTIFICATIONS: Capabilities = Capabilities(0b001);
+    /// The peer supports server-initiated requests/notifications (see `ServerHandle`).
+    pub const STREAMING: Capabilities = Capabilities(0b010);
+    /// The peer is willing to run the `transport::Handshake` transform negotiation.
+    pub const COMPRESSION: Capabilities = Capabilities(0b100);
+
+    fn bits(self) -> u8 {
+        self.0
+    }
+
+    fn from_bits(bits: u8) -> Capabilities {
+        Capabilities(bits)
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// The version and capabilities both peers agreed on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NegotiatedConnection {
+    pub version: ProtocolVersion,
+    pub capabilities: Capabilities,
+}
+
+/// Failure to negotiate a protocol version/capability set with a peer.
+#[derive(Debug)]
+pub enum NegotiationError {
+    /// The peer's major version doesn't match ours; a structured error response has already
+    /// been written back to them.
+    IncompatibleMajorVersion { ours: u8, theirs: u8 },
+    Io(io::Error),
+}
+
+impl From<io::Error> for NegotiationError {
+    fn from(err: io::Error) -> NegotiationError {
+        NegotiationError::Io(err)
+    }
+}
+
+/// What this peer proposes when negotiating a connection.
+pub struct Negotiation {
+    version: ProtocolVersion,
+    capabilities: Capabilities,
+}
+
+impl Negotiation {
+    pub fn new(version: ProtocolVersion, capabilities: Capabilities) -> Negotiation {
+        Negotiation {
+            version,
+            capabilities,
+        }
+    }
+
+    fn frame(&self) -> [u8; 3] {
+        [self.version.major, self.version.minor, self.capabilities.bits()]
+    }
+
+    fn parse_frame(frame: [u8; 3]) -> (ProtocolVersion, Capabilities) {
+        (ProtocolVersion { major: frame[0], minor: frame[1] }, Capabilities::from_bits(frame[2]))
+    }
+
+    fn intersect(&self, peer_version: ProtocolVersion, peer_capabilities: Capabilities)
+                 -> Result<NegotiatedConnection, NegotiationError> {
+        if peer_version.major != self.version.major {
+            return Err(NegotiationError::IncompatibleMajorVersion {
+                ours: self.version.major,
+                theirs: peer_version.major,
+            });
+        }
+
+        Ok(NegotiatedConnection {
+            version: ProtocolVersion {
+                major: self.version.major,
+                minor: cmp::min(self.version.minor, peer_version.minor),
+            },
+            capabilities: self.capabilities & peer_capabilities,
+        })
+    }
+
+    /// Run as the side that accepted the connection: read the peer's proposal, send ours back,
+    /// and either return the intersected capability set or, on an incompatible major version,
+    /// write a structured error response and return an error.
+    pub async fn accept<S>(&self, mut conn: S) -> Result<NegotiatedConnection, NegotiationError>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let mut frame = [0u8; 3];
+        conn.read_exact(&mut frame).await?;
+        let (peer_version, peer_capabilities) = Negotiation::parse_frame(frame);
+
+        conn.write_all(&self.frame()).await?;
+
+        match self.intersect(peer_version, peer_capabilities) {
+            Ok(negotiated) => Ok(negotiated),
+            Err(err) => {
+                let message = Message::Response(Response {
+                    id: 0,
+                    result: Err(Value::from(format!("incompatible protocol major version: \
+                                                      server={}, client={}",
+                                                     self.version.major,
+                                                     peer_version.major))),
+                });
+                conn.write_all(&message.pack()).await?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Run as the side that dialed the connection: send our proposal, read the peer's, and
+    /// return the intersected capability set.
+    pub async fn connect<S>(&self, mut conn: S) -> Result<NegotiatedConnection, NegotiationError>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        conn.write_all(&self.frame()).await?;
+
+        let mut frame = [0u8; 3];
+        conn.read_exact(&mut frame).await?;
+        let (peer_version, peer_capabilities) = Negotiation::parse_frame(frame);
+
+        self.intersect(peer_version, peer_capabilities)
+    }
+}
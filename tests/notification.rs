@@ -0,0 +1,88 @@
+extern crate msgpack_rpc;
+extern crate rmpv;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rmpv::Value;
+
+use msgpack_rpc::{Dispatch, Message, Notification, Server};
+
+#[derive(Clone)]
+struct RecordingServer {
+    notified: mpsc::Sender<(String, Vec<Value>)>,
+}
+
+impl Dispatch for RecordingServer {
+    fn dispatch(&mut self, method: &str, _args: Vec<Value>) -> Result<Value, Value> {
+        Err(Value::from(format!("unexpected request: {}", method)))
+    }
+
+    fn notify(&mut self, method: &str, args: Vec<Value>) {
+        let _ = self.notified.send((method.to_owned(), args));
+    }
+}
+
+#[test]
+fn notification_is_dispatched_without_a_response() {
+    let server = Server::bind("localhost:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let dispatcher = RecordingServer { notified: tx };
+
+    thread::spawn(move || server.handle(dispatcher));
+
+    let mut conn = TcpStream::connect(addr).unwrap();
+
+    // Hand-roll the version/capability negotiation frame: version 1.0, NOTIFICATIONS only (no
+    // COMPRESSION), so notifications are dispatched and the server skips straight to its plain
+    // MsgpackCodec path.
+    conn.write_all(&[1, 0, 0b001]).unwrap();
+    let mut server_frame = [0u8; 3];
+    conn.read_exact(&mut server_frame).unwrap();
+
+    let notification = Message::Notification(Notification {
+        method: "ping".to_owned(),
+        params: vec![Value::from("hello".to_owned())],
+    });
+    conn.write_all(&notification.pack()).unwrap();
+
+    let (method, args) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!("ping", method);
+    assert_eq!(vec![Value::from("hello".to_owned())], args);
+
+    // A notification must never get a response: a short read on the connection should simply
+    // time out rather than return any bytes.
+    conn.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let mut buf = [0u8; 1];
+    let result = conn.read(&mut buf);
+    assert!(result.is_err() || result.unwrap() == 0);
+}
+
+#[test]
+fn notification_is_dropped_without_negotiated_capability() {
+    let server = Server::bind("localhost:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let dispatcher = RecordingServer { notified: tx };
+
+    thread::spawn(move || server.handle(dispatcher));
+
+    let mut conn = TcpStream::connect(addr).unwrap();
+
+    // Advertise no capabilities at all, so the server must not dispatch the notification below.
+    conn.write_all(&[1, 0, 0]).unwrap();
+    let mut server_frame = [0u8; 3];
+    conn.read_exact(&mut server_frame).unwrap();
+
+    let notification = Message::Notification(Notification {
+        method: "ping".to_owned(),
+        params: vec![],
+    });
+    conn.write_all(&notification.pack()).unwrap();
+
+    assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+}